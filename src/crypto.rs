@@ -0,0 +1,374 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha512};
+use std::collections::HashSet;
+use std::fs;
+use std::time::{Duration, SystemTime};
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+
+// key rotation defaults. rotation fires on whichever of these comes first.
+pub const KEY_ROTATION_INTERVAL: Duration = Duration::from_secs(300);
+pub const KEY_ROTATION_PACKET_LIMIT: u64 = 50_000;
+// the previous session key is kept around briefly after a rotation so packets
+// already in flight under it aren't dropped as undecryptable garbage.
+pub const PREVIOUS_KEY_GRACE: Duration = Duration::from_secs(5);
+
+// how long a caller waiting on a handshake reply should wait before treating
+// its init frame as lost and sending another - UDP delivery isn't guaranteed
+// and nothing else retries a dropped one.
+pub const HANDSHAKE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+const BASE62_ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+// First byte of every datagram sent over the encrypted transport. Plaintext
+// JSON frames always start with `{` (0x7B), so these low values let
+// `poll_messages` route handshake/rotation/data frames before a JSON parse
+// is even attempted.
+pub const FRAME_HANDSHAKE_INIT: u8 = 0x01;
+pub const FRAME_KEY_ROTATE: u8 = 0x02;
+pub const FRAME_ENCRYPTED: u8 = 0x03;
+
+// extra bytes `encode_data_frame` adds on top of the plaintext payload once
+// it's gone through `PeerSession::encrypt`: the 1-byte frame tag, the 8-byte
+// counter, and ChaCha20Poly1305's 16-byte authentication tag. Callers that
+// need to keep an encrypted send under some fixed size (e.g. a UDP recv
+// buffer) need to budget for this on top of the plaintext's own size.
+pub const ENCRYPTED_FRAME_OVERHEAD: usize = 1 + 8 + 16;
+
+pub fn base62_encode(bytes: &[u8]) -> String {
+    let mut digits = bytes.to_vec();
+    let mut out = Vec::new();
+    while digits.iter().any(|&d| d != 0) {
+        let mut remainder: u32 = 0;
+        for d in digits.iter_mut() {
+            let acc = (remainder << 8) | *d as u32;
+            *d = (acc / 62) as u8;
+            remainder = acc % 62;
+        }
+        out.push(BASE62_ALPHABET[remainder as usize]);
+    }
+    if out.is_empty() {
+        out.push(BASE62_ALPHABET[0]);
+    }
+    out.reverse();
+    String::from_utf8(out).unwrap()
+}
+
+pub fn base62_decode(s: &str, expected_len: usize) -> Option<[u8; 32]> {
+    let mut bytes = vec![0u8; expected_len];
+    for c in s.bytes() {
+        let digit = BASE62_ALPHABET.iter().position(|&b| b == c)? as u32;
+        let mut carry = digit;
+        for b in bytes.iter_mut().rev() {
+            let acc = (*b as u32) * 62 + carry;
+            *b = (acc & 0xFF) as u8;
+            carry = acc >> 8;
+        }
+        if carry != 0 {
+            return None;
+        }
+    }
+    bytes.try_into().ok()
+}
+
+// A peer's long-lived Ed25519 identity. The same seed also derives the
+// X25519 static secret used for ECDH, via the standard clamped-SHA-512
+// expansion (the same derivation ed25519 itself uses to turn a seed into a
+// scalar), so a peer only has one keypair to generate and persist.
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        Identity {
+            signing_key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    pub fn from_seed_base62(seed_b62: &str) -> Option<Self> {
+        let seed = base62_decode(seed_b62, 32)?;
+        Some(Identity {
+            signing_key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    pub fn seed_base62(&self) -> String {
+        base62_encode(&self.signing_key.to_bytes())
+    }
+
+    pub fn public_base62(&self) -> String {
+        base62_encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    fn x25519_secret(&self) -> StaticSecret {
+        let hash = Sha512::digest(self.signing_key.to_bytes());
+        let mut scalar = [0u8; 32];
+        scalar.copy_from_slice(&hash[..32]);
+        scalar[0] &= 248;
+        scalar[31] &= 127;
+        scalar[31] |= 64;
+        StaticSecret::from(scalar)
+    }
+
+    pub fn x25519_public(&self) -> X25519PublicKey {
+        X25519PublicKey::from(&self.x25519_secret())
+    }
+
+    pub fn diffie_hellman(&self, their_public: &X25519PublicKey) -> [u8; 32] {
+        self.x25519_secret().diffie_hellman(their_public).to_bytes()
+    }
+}
+
+pub fn public_key_from_base62(s: &str) -> Option<VerifyingKey> {
+    VerifyingKey::from_bytes(&base62_decode(s, 32)?).ok()
+}
+
+// an empty allowlist (missing file, or no parseable lines) means trust any
+// identity on first contact; a non-empty one restricts the registrar to the
+// listed peers.
+pub fn load_known_identities(path: &str) -> HashSet<VerifyingKey> {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return HashSet::new();
+    };
+    contents
+        .lines()
+        .filter_map(|line| public_key_from_base62(line.trim()))
+        .collect()
+}
+
+// Per-peer encrypted session state. `cipher` is the active session key;
+// `previous_cipher` is the one it replaced, kept alive for `PREVIOUS_KEY_GRACE`.
+pub struct PeerSession {
+    pub remote_identity: VerifyingKey,
+    pub remote_x25519_public: X25519PublicKey,
+    cipher: ChaCha20Poly1305,
+    previous_cipher: Option<ChaCha20Poly1305>,
+    previous_cipher_expires: Option<SystemTime>,
+    // which side of the handshake this end was. Both ends share one cipher
+    // key, so the nonce has to be told the two directions apart some other
+    // way - the initiator's outgoing frames use direction 0 (and it expects
+    // the responder's incoming frames under direction 1), while the
+    // responder does the reverse. Keying the direction byte off which local
+    // function is called (`encrypt` vs `decrypt`) instead of this would have
+    // both ends agree with themselves and disagree with each other.
+    is_initiator: bool,
+    send_nonce: u64,
+    established_at: SystemTime,
+    packets_since_rotation: u64,
+}
+
+fn cipher_from_shared_secret(shared_secret: &[u8; 32]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(shared_secret))
+}
+
+fn frame_nonce(counter: u64, direction: u8) -> Nonce {
+    let mut bytes = [0u8; 12];
+    bytes[0] = direction;
+    bytes[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&bytes)
+}
+
+impl PeerSession {
+    // `is_initiator` just needs to land opposite on the two ends of a
+    // session - callers decide it however they like (e.g. a fixed order over
+    // the two sides' identities), as long as both sides agree without
+    // needing to compare notes.
+    pub fn new(
+        shared_secret: [u8; 32],
+        remote_identity: VerifyingKey,
+        remote_x25519_public: X25519PublicKey,
+        is_initiator: bool,
+    ) -> Self {
+        PeerSession {
+            remote_identity,
+            remote_x25519_public,
+            cipher: cipher_from_shared_secret(&shared_secret),
+            previous_cipher: None,
+            previous_cipher_expires: None,
+            is_initiator,
+            send_nonce: 0,
+            established_at: SystemTime::now(),
+            packets_since_rotation: 0,
+        }
+    }
+
+    pub fn should_rotate(&self) -> bool {
+        self.established_at.elapsed().unwrap_or_default() > KEY_ROTATION_INTERVAL
+            || self.packets_since_rotation > KEY_ROTATION_PACKET_LIMIT
+    }
+
+    pub fn rotate(&mut self, new_shared_secret: [u8; 32]) {
+        let old_cipher = std::mem::replace(
+            &mut self.cipher,
+            cipher_from_shared_secret(&new_shared_secret),
+        );
+        self.previous_cipher = Some(old_cipher);
+        self.previous_cipher_expires = Some(SystemTime::now() + PREVIOUS_KEY_GRACE);
+        self.established_at = SystemTime::now();
+        self.packets_since_rotation = 0;
+        self.send_nonce = 0;
+    }
+
+    // encrypts `plaintext` and returns the counter it was sent under, so the
+    // caller can place it in the wire frame alongside the ciphertext.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> (u64, Vec<u8>) {
+        let counter = self.send_nonce;
+        self.send_nonce += 1;
+        self.packets_since_rotation += 1;
+        let direction = if self.is_initiator { 0 } else { 1 };
+        let ciphertext = self
+            .cipher
+            .encrypt(&frame_nonce(counter, direction), plaintext)
+            .expect("chacha20poly1305 encryption failure");
+        (counter, ciphertext)
+    }
+
+    pub fn decrypt(&self, counter: u64, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let direction = if self.is_initiator { 1 } else { 0 };
+        let nonce = frame_nonce(counter, direction);
+        if let Ok(plaintext) = self.cipher.decrypt(&nonce, ciphertext) {
+            return Some(plaintext);
+        }
+        if let (Some(prev), Some(expires)) = (&self.previous_cipher, self.previous_cipher_expires)
+        {
+            if SystemTime::now() < expires {
+                return prev.decrypt(&nonce, ciphertext).ok();
+            }
+        }
+        None
+    }
+}
+
+pub fn encode_init_frame(identity: &Identity) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + 32 + 32);
+    out.push(FRAME_HANDSHAKE_INIT);
+    out.extend_from_slice(identity.verifying_key().as_bytes());
+    out.extend_from_slice(identity.x25519_public().as_bytes());
+    out
+}
+
+pub fn decode_init_frame(data: &[u8]) -> Option<(VerifyingKey, X25519PublicKey)> {
+    if data.len() != 65 || data[0] != FRAME_HANDSHAKE_INIT {
+        return None;
+    }
+    let identity_bytes: [u8; 32] = data[1..33].try_into().ok()?;
+    let x25519_bytes: [u8; 32] = data[33..65].try_into().ok()?;
+    let identity = VerifyingKey::from_bytes(&identity_bytes).ok()?;
+    Some((identity, X25519PublicKey::from(x25519_bytes)))
+}
+
+pub fn encode_rotate_frame(ephemeral_public: &X25519PublicKey) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + 32);
+    out.push(FRAME_KEY_ROTATE);
+    out.extend_from_slice(ephemeral_public.as_bytes());
+    out
+}
+
+pub fn decode_rotate_frame(data: &[u8]) -> Option<X25519PublicKey> {
+    if data.len() != 33 || data[0] != FRAME_KEY_ROTATE {
+        return None;
+    }
+    let bytes: [u8; 32] = data[1..33].try_into().ok()?;
+    Some(X25519PublicKey::from(bytes))
+}
+
+pub fn encode_data_frame(counter: u64, ciphertext: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + 8 + ciphertext.len());
+    out.push(FRAME_ENCRYPTED);
+    out.extend_from_slice(&counter.to_be_bytes());
+    out.extend_from_slice(ciphertext);
+    out
+}
+
+pub fn decode_data_frame(data: &[u8]) -> Option<(u64, &[u8])> {
+    if data.len() < 9 || data[0] != FRAME_ENCRYPTED {
+        return None;
+    }
+    let counter = u64::from_be_bytes(data[1..9].try_into().ok()?);
+    Some((counter, &data[9..]))
+}
+
+// ephemeral X25519 keypair used to re-key a session on rotation. The new
+// session key is the DH of this fresh secret with the peer's static X25519
+// key, so it doesn't depend on the rotating side's identity key; anyone who
+// later holds the peer's identity seed and saw the rotate frame can still
+// derive it.
+pub struct EphemeralSecret(x25519_dalek::EphemeralSecret);
+
+impl EphemeralSecret {
+    pub fn generate() -> (Self, X25519PublicKey) {
+        let secret = x25519_dalek::EphemeralSecret::random_from_rng(OsRng);
+        let public = X25519PublicKey::from(&secret);
+        (EphemeralSecret(secret), public)
+    }
+
+    pub fn diffie_hellman(self, their_public: &X25519PublicKey) -> [u8; 32] {
+        self.0.diffie_hellman(their_public).to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a session handshake always agrees on a shared secret via ECDH, so a
+    // fixed one here stands in for that without needing a full handshake.
+    const SHARED_SECRET: [u8; 32] = [7u8; 32];
+
+    #[test]
+    fn initiator_and_responder_round_trip() {
+        let initiator_identity = Identity::generate();
+        let responder_identity = Identity::generate();
+        let mut initiator = PeerSession::new(
+            SHARED_SECRET,
+            responder_identity.verifying_key(),
+            responder_identity.x25519_public(),
+            true,
+        );
+        let responder = PeerSession::new(
+            SHARED_SECRET,
+            initiator_identity.verifying_key(),
+            initiator_identity.x25519_public(),
+            false,
+        );
+
+        let (counter, ciphertext) = initiator.encrypt(b"hello from the initiator");
+        let plaintext = responder
+            .decrypt(counter, &ciphertext)
+            .expect("responder should decrypt the initiator's frame");
+        assert_eq!(plaintext, b"hello from the initiator");
+    }
+
+    #[test]
+    fn responder_and_initiator_round_trip() {
+        let initiator_identity = Identity::generate();
+        let responder_identity = Identity::generate();
+        let initiator = PeerSession::new(
+            SHARED_SECRET,
+            responder_identity.verifying_key(),
+            responder_identity.x25519_public(),
+            true,
+        );
+        let mut responder = PeerSession::new(
+            SHARED_SECRET,
+            initiator_identity.verifying_key(),
+            initiator_identity.x25519_public(),
+            false,
+        );
+
+        let (counter, ciphertext) = responder.encrypt(b"hello from the responder");
+        let plaintext = initiator
+            .decrypt(counter, &ciphertext)
+            .expect("initiator should decrypt the responder's frame");
+        assert_eq!(plaintext, b"hello from the responder");
+    }
+}
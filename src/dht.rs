@@ -0,0 +1,215 @@
+use ed25519_dalek::VerifyingKey;
+use sha2::{Digest, Sha256};
+use std::time::SystemTime;
+
+// bucket size (k) and the number of registrars queried per FIND_NODE round (alpha).
+pub const K_BUCKET_SIZE: usize = 16;
+pub const ALPHA: usize = 3;
+// an iterative lookup gives up after this many rounds even if the closest
+// set hasn't stabilized, bounding worst-case latency on a stale/sparse mesh.
+pub const MAX_LOOKUP_STEPS: u32 = 8;
+// if a queried registrar hasn't answered within this long, its slot in the
+// round is abandoned so the lookup can move on rather than stalling on one
+// unresponsive peer until the much longer overall lookup timeout fires.
+pub const ROUND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NodeId(pub [u8; 32]);
+
+impl NodeId {
+    pub fn from_verifying_key(key: &VerifyingKey) -> Self {
+        NodeId(key.to_bytes())
+    }
+
+    // placeholder id for a bootstrap registrar we haven't exchanged a real
+    // node id with yet; replaced in the routing table as soon as that
+    // registrar's own id arrives on a `DhtLookupRequest`/`DhtLookupResponse`.
+    pub fn from_addr(addr: &str) -> Self {
+        NodeId(Sha256::digest(addr.as_bytes()).into())
+    }
+
+    // host codes are hashed into the same 256-bit key space as node ids, so
+    // "find the registrar closest to this code" and "find the registrar
+    // closest to that node" are the same walk over the routing table.
+    pub fn from_host_code(host_code: &str) -> Self {
+        NodeId(Sha256::digest(host_code.as_bytes()).into())
+    }
+
+    pub fn to_base62(self) -> String {
+        crate::crypto::base62_encode(&self.0)
+    }
+
+    pub fn from_base62(s: &str) -> Option<Self> {
+        crate::crypto::base62_decode(s, 32).map(NodeId)
+    }
+
+    fn distance(&self, other: &NodeId) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        for i in 0..32 {
+            out[i] = self.0[i] ^ other.0[i];
+        }
+        out
+    }
+
+    // which of the 256 k-buckets a peer at this distance falls into: the bit
+    // position (counted from the most significant bit of the distance) where
+    // the two ids first differ - so a peer only shares that many leading
+    // bits with self, and the lower that position is, the more distant the
+    // peer. Bucket 0 holds the furthest peers, bucket 255 the closest. This
+    // also means the low buckets absorb most of the keyspace (half of all
+    // random ids differ from self in the very first bit) and are where
+    // `RoutingTable::insert`'s per-bucket k=16 cap does most of its
+    // evicting, while the rare, valuable near neighbors sit in the high
+    // buckets, which almost never fill.
+    fn bucket_index(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        for (byte_index, byte) in distance.iter().enumerate() {
+            if *byte != 0 {
+                return Some(byte_index * 8 + byte.leading_zeros() as usize);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone)]
+pub struct RegistrarPeer {
+    pub node_id: NodeId,
+    pub addr: String,
+    pub last_seen: SystemTime,
+}
+
+// k-buckets indexed by XOR-distance bit-position from this registrar's own id.
+pub struct RoutingTable {
+    self_id: NodeId,
+    buckets: Vec<Vec<RegistrarPeer>>,
+}
+
+impl RoutingTable {
+    pub fn new(self_id: NodeId) -> Self {
+        RoutingTable {
+            self_id,
+            buckets: (0..256).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn insert(&mut self, node_id: NodeId, addr: String) {
+        if node_id == self.self_id {
+            return;
+        }
+        let Some(index) = self.self_id.bucket_index(&node_id) else {
+            return;
+        };
+        // a bootstrap entry seeded under `NodeId::from_addr` is replaced here
+        // once the registrar's real id is learned, rather than left behind
+        // as a stale duplicate of the same physical peer.
+        for bucket in &mut self.buckets {
+            bucket.retain(|peer| peer.addr != addr || peer.node_id == node_id);
+        }
+        let bucket = &mut self.buckets[index];
+        bucket.retain(|peer| peer.node_id != node_id);
+        if bucket.len() >= K_BUCKET_SIZE {
+            bucket.remove(0);
+        }
+        bucket.push(RegistrarPeer {
+            node_id,
+            addr,
+            last_seen: SystemTime::now(),
+        });
+    }
+
+    pub fn expire_stale(&mut self, timeout: std::time::Duration) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|peer| peer.last_seen.elapsed().unwrap_or_default() < timeout);
+        }
+    }
+
+    // the `count` known registrars closest to `target`, nearest first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<RegistrarPeer> {
+        let mut all: Vec<&RegistrarPeer> = self.buckets.iter().flatten().collect();
+        all.sort_by_key(|peer| peer.node_id.distance(target));
+        all.into_iter().take(count).cloned().collect()
+    }
+}
+
+// state of one iterative FIND_NODE/FIND_VALUE walk in flight, keyed by a
+// per-lookup id so responses from different registrars can be matched back
+// to the client that's waiting on them.
+pub struct PendingLookup {
+    pub host_code: String,
+    pub target: NodeId,
+    pub originator_addr: String,
+    pub queried: Vec<NodeId>,
+    pub closest: Vec<RegistrarPeer>,
+    pub awaiting: Vec<NodeId>,
+    pub step: u32,
+    pub started_at: SystemTime,
+    pub round_started_at: SystemTime,
+}
+
+impl PendingLookup {
+    pub fn new(
+        host_code: String,
+        originator_addr: String,
+        routing_table: &RoutingTable,
+    ) -> Self {
+        let target = NodeId::from_host_code(&host_code);
+        let now = SystemTime::now();
+        PendingLookup {
+            host_code,
+            target,
+            originator_addr,
+            queried: Vec::new(),
+            closest: routing_table.closest(&target, K_BUCKET_SIZE),
+            awaiting: Vec::new(),
+            step: 0,
+            started_at: now,
+            round_started_at: now,
+        }
+    }
+
+    pub fn round_timed_out(&self) -> bool {
+        !self.awaiting.is_empty() && self.round_started_at.elapsed().unwrap_or_default() > ROUND_TIMEOUT
+    }
+
+    // abandons any registrars from the current round that never answered,
+    // so the next `next_to_query()` call can move on to fresh candidates.
+    pub fn abandon_round(&mut self) {
+        self.awaiting.clear();
+    }
+
+    // next batch of registrars to query: the closest ones we haven't asked yet.
+    pub fn next_to_query(&mut self) -> Vec<RegistrarPeer> {
+        let picked: Vec<RegistrarPeer> = self
+            .closest
+            .iter()
+            .filter(|peer| !self.queried.contains(&peer.node_id))
+            .take(ALPHA)
+            .cloned()
+            .collect();
+        for peer in &picked {
+            self.queried.push(peer.node_id);
+            self.awaiting.push(peer.node_id);
+        }
+        self.step += 1;
+        self.round_started_at = SystemTime::now();
+        picked
+    }
+
+    pub fn merge_closer_nodes(&mut self, nodes: Vec<RegistrarPeer>) {
+        for node in nodes {
+            if !self.closest.iter().any(|peer| peer.node_id == node.node_id) {
+                self.closest.push(node);
+            }
+        }
+        self.closest
+            .sort_by_key(|peer| peer.node_id.distance(&self.target));
+        self.closest.truncate(K_BUCKET_SIZE);
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.awaiting.is_empty()
+            && (self.step >= MAX_LOOKUP_STEPS
+                || self.closest.iter().all(|peer| self.queried.contains(&peer.node_id)))
+    }
+}
@@ -2,15 +2,164 @@ use nanoid::nanoid;
 use proto::EnetAddr;
 use proto::EnetHost;
 use proto::SocketAgnosticInterface;
-use serde_json::Value;
 use std::collections::HashMap;
-use std::time::SystemTime;
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+mod crypto;
+mod dht;
 mod proto;
+mod wire;
+
+const KNOWN_PEERS_FILE: &str = "known_peers.txt";
+const KNOWN_REGISTRARS_FILE: &str = "known_registrars.txt";
+const IDENTITY_SEED_ENV: &str = "HOSTREGISTER_IDENTITY_SEED";
+
+// sibling registrars to bootstrap the routing table from, one `addr` per
+// line. their real node ids aren't known yet at this point, so they're
+// seeded under a placeholder derived from the address and corrected the
+// first time that registrar's own id arrives on a dht message.
+fn load_known_registrars(path: &str) -> Vec<String> {
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn load_or_generate_identity() -> crypto::Identity {
+    if let Ok(seed) = std::env::var(IDENTITY_SEED_ENV) {
+        if let Some(identity) = crypto::Identity::from_seed_base62(&seed) {
+            return identity;
+        }
+        println!("{IDENTITY_SEED_ENV} is set but not a valid seed, generating a new identity");
+    }
+    let identity = crypto::Identity::generate();
+    println!(
+        "generated a new registrar identity (persist it with {IDENTITY_SEED_ENV}={})",
+        identity.seed_base62()
+    );
+    println!("public key: {}", identity.public_base62());
+    identity
+}
+
+// encodes `msg` and sends it to `target`, encrypting it through an
+// established peer session when one exists. Peers that haven't completed a
+// handshake still get the plaintext frame, same as before this transport
+// existed (when that frame was always JSON instead) - but the first time
+// that happens for a given target, a handshake init is sent its way too (and
+// resent every `HANDSHAKE_RETRY_INTERVAL` until a session is established, in
+// case the first one was lost), so a registrar that only ever dials out
+// (pings to hosts, DhtLookupRequests to siblings) doesn't wait forever for
+// the other side to speak first before its own outbound traffic gets
+// encrypted. `peer_wire_formats` replies in whichever format `target` was
+// last seen using - JSON for a peer still on the previous protocol version,
+// the compact binary codec otherwise - defaulting to binary for a target
+// we've never heard from.
+fn send_message(
+    enet_host: &mut EnetHost,
+    peer_sessions: &mut HashMap<String, crypto::PeerSession>,
+    peer_wire_formats: &HashMap<String, wire::Format>,
+    pending_handshakes: &mut HashMap<String, SystemTime>,
+    identity: &crypto::Identity,
+    target: &str,
+    msg: &proto::MsgType,
+) {
+    if !peer_sessions.contains_key(target) {
+        let already_tried = pending_handshakes
+            .get(target)
+            .map(|sent_at| sent_at.elapsed().unwrap_or_default() < crypto::HANDSHAKE_RETRY_INTERVAL)
+            .unwrap_or(false);
+        if !already_tried {
+            if let Err(err) =
+                enet_host.send_to_target(&crypto::encode_init_frame(identity), target.to_string())
+            {
+                println!("failed to send handshake init to {target}: {err}");
+            }
+            pending_handshakes.insert(target.to_string(), SystemTime::now());
+        }
+    }
+    let format = peer_wire_formats
+        .get(target)
+        .copied()
+        .unwrap_or(wire::Format::Binary);
+    let data_to_send = wire::encode(msg, format);
+    if data_to_send.is_empty() {
+        return;
+    }
+    let frame = match peer_sessions.get_mut(target) {
+        Some(session) => {
+            let (counter, ciphertext) = session.encrypt(&data_to_send);
+            crypto::encode_data_frame(counter, &ciphertext)
+        }
+        None => data_to_send,
+    };
+    // `target` can be sourced from another registrar's DHT gossip, so a
+    // send failure (e.g. an address that slipped through validation) is
+    // dropped rather than allowed to panic the whole registrar.
+    if let Err(err) = enet_host.send_to_target(&frame, target.to_string()) {
+        println!("failed to send to {target}: {err}");
+    }
+}
+
+// decodes a frame's payload (either a plaintext on-wire frame, or the
+// already-decrypted body of an encrypted one) into a `MsgType` plus the
+// format it was found in, trying the compact binary codec first and falling
+// back to JSON so older peers still speaking the previous protocol version
+// keep working. `None` covers every failure path uniformly - a short or
+// malformed binary frame, invalid UTF-8, or unparseable JSON - rather than
+// the old `Value::Null`/`unwrap_or_default` checks that silently swallowed
+// some of those cases.
+fn decode_message(payload: &[u8]) -> Option<(proto::MsgType, wire::Format)> {
+    if let Some(msg) = wire::decode(payload) {
+        return Some((msg, wire::Format::Binary));
+    }
+    let text = std::str::from_utf8(payload).ok()?;
+    let msg = serde_json::from_str(text).ok()?;
+    Some((msg, wire::Format::Json))
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+// sends the next batch of `DhtLookupRequest`s for a pending lookup (the
+// closest still-unqueried registrars known so far).
+fn advance_lookup(
+    enet_host: &mut EnetHost,
+    peer_sessions: &mut HashMap<String, crypto::PeerSession>,
+    peer_wire_formats: &HashMap<String, wire::Format>,
+    pending_handshakes: &mut HashMap<String, SystemTime>,
+    identity: &crypto::Identity,
+    self_node_id: &str,
+    lookup_id: &str,
+    lookup: &mut dht::PendingLookup,
+) {
+    for peer in lookup.next_to_query() {
+        let request = proto::MsgType::DhtLookupRequest {
+            lookup_id: lookup_id.to_string(),
+            host_code: lookup.host_code.clone(),
+            from_node_id: self_node_id.to_string(),
+        };
+        send_message(
+            enet_host,
+            peer_sessions,
+            peer_wire_formats,
+            pending_handshakes,
+            identity,
+            &peer.addr,
+            &request,
+        );
+    }
+}
 
 fn main() {
     // socket setup (could be any socket implementing the SAI in proto.rs)
-    let mut enet_host = EnetHost::init(4422, 500);
+    let mut enet_host = EnetHost::init(Ipv4Addr::UNSPECIFIED, 4422, 500);
     // host id alphabet
     let host_id_length = 8;
     let host_alphabet: [char; 16] = [
@@ -19,20 +168,58 @@ fn main() {
     // host bookkeeping
     let mut host_register: HashMap<String, proto::Host> = HashMap::new();
     let mut host_map: HashMap<String, String> = HashMap::new();
-    
-    let mut buf = [0; 1024];
+
+    // encrypted transport state
+    let identity = load_or_generate_identity();
+    let known_identities = crypto::load_known_identities(KNOWN_PEERS_FILE);
+    let mut peer_sessions: HashMap<String, crypto::PeerSession> = HashMap::new();
+    // which wire format each peer was last seen sending, so replies match
+    // rather than assuming every peer already speaks the binary codec.
+    let mut peer_wire_formats: HashMap<String, wire::Format> = HashMap::new();
+    // targets `send_message` has sent a handshake init to, and when, so it
+    // doesn't resend one on every message to the same still-pending peer -
+    // unless `HANDSHAKE_RETRY_INTERVAL` has since passed, in which case the
+    // original init is assumed lost and another goes out.
+    let mut pending_handshakes: HashMap<String, SystemTime> = HashMap::new();
+
+    // federated lookup state: a Kademlia-style routing table of sibling
+    // registrars, and the lookups currently walking it.
+    let self_node_id = dht::NodeId::from_verifying_key(&identity.verifying_key());
+    let mut routing_table = dht::RoutingTable::new(self_node_id);
+    let self_node_id_b62 = self_node_id.to_base62();
+    for addr in load_known_registrars(KNOWN_REGISTRARS_FILE) {
+        routing_table.insert(dht::NodeId::from_addr(&addr), addr);
+    }
+    let mut pending_lookups: HashMap<String, dht::PendingLookup> = HashMap::new();
+    // hole-punch coordination windows opened by successful local lookups.
+    let mut pending_punches: Vec<proto::PendingPunch> = Vec::new();
+
+    let mut buf = [0; proto::RECV_BUFFER_SIZE];
     loop {
         // cleanup clients
         enet_host.check_and_cleanup_clients();
+        // `poll_messages` never surfaces ENet's own Connect/Disconnect events
+        // to its caller, so encrypted-transport state keyed by peer address
+        // is pruned here instead, against the host's actual live peer list -
+        // otherwise every distinct address that ever completes a handshake
+        // accumulates in these maps forever, unlike every other per-peer map
+        // in this file.
+        let connected_addrs = enet_host.connected_addrs();
+        peer_sessions.retain(|addr, _| connected_addrs.contains(addr));
+        peer_wire_formats.retain(|addr, _| connected_addrs.contains(addr));
+        // `pending_handshakes` is pruned against `known_peer_addrs`, not
+        // `connected_addrs` - a target `send_message` just dialed out to
+        // sits in `PeerState::Connecting` for at least a round trip, and
+        // pruning it here too early would drop its "already sent" bookkeeping
+        // and have it resend a handshake init on every tick until the ENet
+        // connection actually completes.
+        let known_peer_addrs = enet_host.known_peer_addrs();
+        pending_handshakes.retain(|addr, _| known_peer_addrs.contains(addr));
         // send pings to all hosts to see if theyre still active.
         for (_, host) in &mut host_register {
             if host.should_send_ping() {
                 let response = proto::MsgType::PingResponse;
-                let data_to_send = serde_json::to_string(&response).unwrap_or_default();
-                if !data_to_send.is_empty() {
-                    enet_host.send_to_target(data_to_send.as_bytes(), host.addr.clone())
-                        .unwrap();
-                }
+                send_message(&mut enet_host, &mut peer_sessions, &peer_wire_formats, &mut pending_handshakes, &identity, &host.addr, &response);
             }
             // check if cleanup is needed.
             if host.should_be_removed() {
@@ -56,118 +243,527 @@ fn main() {
             }
             !host.delete_later
         });
+        // rotate session keys for peers that are due, same "every_second"-style
+        // tick as the ping/cleanup passes above.
+        let due_for_rotation: Vec<String> = peer_sessions
+            .iter()
+            .filter(|(_, session)| session.should_rotate())
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in due_for_rotation {
+            let Some(session) = peer_sessions.get_mut(&addr) else {
+                continue;
+            };
+            let (ephemeral_secret, ephemeral_public) = crypto::EphemeralSecret::generate();
+            let shared_secret = ephemeral_secret.diffie_hellman(&session.remote_x25519_public);
+            session.rotate(shared_secret);
+            if let Err(err) = enet_host
+                .send_to_target(&crypto::encode_rotate_frame(&ephemeral_public), addr.clone())
+            {
+                println!("failed to send key rotation to {addr}: {err}");
+            }
+        }
+        // expire stale routing-table entries and time out lookups that never
+        // heard back, both on the same timeout used for ping cleanup above.
+        routing_table.expire_stale(proto::PEER_REMOVAL_TIMEMOUT);
+        let timed_out: Vec<String> = pending_lookups
+            .iter()
+            .filter(|(_, lookup)| {
+                lookup.started_at.elapsed().unwrap_or_default() > proto::PEER_REMOVAL_TIMEMOUT
+            })
+            .map(|(lookup_id, _)| lookup_id.clone())
+            .collect();
+        for lookup_id in timed_out {
+            if let Some(lookup) = pending_lookups.remove(&lookup_id) {
+                let response = proto::MsgType::HostLookupResponse {
+                    success: false,
+                    host_info: String::new(),
+                    punch_at: 0,
+                };
+                send_message(
+                    &mut enet_host,
+                    &mut peer_sessions,
+                    &peer_wire_formats,
+                    &mut pending_handshakes,
+                    &identity,
+                    &lookup.originator_addr,
+                    &response,
+                );
+            }
+        }
+        // a round that's gone quiet (some queried registrar never answered)
+        // gets abandoned rather than stalling the lookup until the much
+        // longer timeout above fires.
+        let stalled_rounds: Vec<String> = pending_lookups
+            .iter()
+            .filter(|(_, lookup)| lookup.round_timed_out())
+            .map(|(lookup_id, _)| lookup_id.clone())
+            .collect();
+        for lookup_id in stalled_rounds {
+            let Some(lookup) = pending_lookups.get_mut(&lookup_id) else {
+                continue;
+            };
+            lookup.abandon_round();
+            if lookup.is_exhausted() {
+                let lookup = pending_lookups.remove(&lookup_id).unwrap();
+                let response = proto::MsgType::HostLookupResponse {
+                    success: false,
+                    host_info: String::new(),
+                    punch_at: 0,
+                };
+                send_message(
+                    &mut enet_host,
+                    &mut peer_sessions,
+                    &peer_wire_formats,
+                    &mut pending_handshakes,
+                    &identity,
+                    &lookup.originator_addr,
+                    &response,
+                );
+            } else {
+                advance_lookup(
+                    &mut enet_host,
+                    &mut peer_sessions,
+                    &peer_wire_formats,
+                    &mut pending_handshakes,
+                    &identity,
+                    &self_node_id_b62,
+                    &lookup_id,
+                    lookup,
+                );
+            }
+        }
+        // punch windows that passed with no sign the hole-punch worked fall
+        // back to telling both sides a relay is needed.
+        let (expired_punches, still_pending): (Vec<_>, Vec<_>) = pending_punches
+            .into_iter()
+            .partition(proto::PendingPunch::is_expired);
+        pending_punches = still_pending;
+        for punch in expired_punches {
+            let notice = proto::MsgType::PunchTimeoutNotice { relay_needed: true };
+            send_message(&mut enet_host, &mut peer_sessions, &peer_wire_formats, &mut pending_handshakes, &identity, &punch.host_addr, &notice);
+            send_message(&mut enet_host, &mut peer_sessions, &peer_wire_formats, &mut pending_handshakes, &identity, &punch.client_addr, &notice);
+        }
         // poll socket. on err just continue.
         let (len, addr) = enet_host.poll_messages(&mut buf).unwrap_or((0, String::new()));
         if len == 0 {
             continue;
         }
-        // get the required slice of the request
-        let Ok(data_str) = std::str::from_utf8(&buf[..len]) else {
-            continue;
-        };
-        // parse the string of data into serde_json::Value.
-        let request: Value = serde_json::from_str(&data_str).unwrap_or_default();
-        if request == Value::Null {
-            continue;
-        }
-        // handle request concurrently if available
-        if request["msg_type"] == Value::Null {
-            continue;
-        }
-        // Ping
-        if request["msg_type"] == "PingRequest" {
-            // check if ping comes from a host
-            let host_id = host_map.get(&addr);
-            if host_id.is_none() {
+        let frame = &buf[..len];
+        // route the reserved encrypted-transport frame types before attempting
+        // to decode a message; plaintext frames start with either the binary
+        // codec's `wire::BINARY_MAGIC` or JSON's leading `{` (0x7B).
+        if frame[0] == crypto::FRAME_HANDSHAKE_INIT {
+            let Some((their_identity, their_x25519_public)) = crypto::decode_init_frame(frame)
+            else {
+                continue;
+            };
+            if !known_identities.is_empty() && !known_identities.contains(&their_identity) {
+                println!("rejected handshake from {addr:?}: identity not in {KNOWN_PEERS_FILE}");
+                enet_host.disconnect_addr(&addr);
                 continue;
             }
-            if let Some(host_info) = host_register.get_mut(host_id.unwrap()) {
-                host_info.last_received_ping = SystemTime::now();
-                let response = proto::MsgType::PingResponse;
-                let data_to_send = serde_json::to_string(&response).unwrap_or_default();
-                if !data_to_send.is_empty() {
-                    enet_host.send_to_target(data_to_send.as_bytes(), addr.clone())
-                        .unwrap();
+            // a peer can resend its init (e.g. after a reconnect, or bouncing
+            // our own reply back if it mistakes it for a fresh one) - only
+            // (re)build the session when we don't already have one under
+            // this exact identity, so a duplicate doesn't silently reset an
+            // established session's nonce counter back to zero under the
+            // same key.
+            if peer_sessions.get(&addr).map(|s| s.remote_identity) != Some(their_identity) {
+                let shared_secret = identity.diffie_hellman(&their_x25519_public);
+                // both sides settle on the same role from a fixed order over
+                // identity bytes, rather than from whichever one's init frame
+                // happens to land first - two registrars can easily dial
+                // each other at about the same time (e.g. answering each
+                // other's DhtLookupRequests), and "whoever sent first is the
+                // initiator" would let both end up thinking they are,
+                // disagreeing on every nonce direction.
+                let is_initiator = identity.verifying_key().as_bytes() < their_identity.as_bytes();
+                let session = crypto::PeerSession::new(
+                    shared_secret,
+                    their_identity,
+                    their_x25519_public,
+                    is_initiator,
+                );
+                peer_sessions.insert(addr.clone(), session);
+            }
+            // if we'd already sent our own init to this peer (tracked in
+            // `pending_handshakes` by `send_message`), this is that peer's
+            // reply and it already has what it needs from us - replying
+            // again here would have both sides echo init frames at each
+            // other forever.
+            if pending_handshakes.remove(&addr).is_none() {
+                if let Err(err) =
+                    enet_host.send_to_target(&crypto::encode_init_frame(&identity), addr.clone())
+                {
+                    println!("failed to send handshake reply to {addr}: {err}");
                 }
             }
             continue;
         }
-        // HostRegisterRequest
-        if request["msg_type"] == "HostRegisterRequest" {
-            let mut id = nanoid!(host_id_length, &host_alphabet);
-            // check if the host_map already has an id for this socket
-            if let Some(host_id) = host_map.get(&addr) {
-                id = host_id.clone();
-                if let Some(host) = host_register.get_mut(&id) {
-                    host.last_received_ping = SystemTime::now();
-                }
-            } else {
-                while host_register.get(&id).is_some() {
-                    id = nanoid!(host_id_length, &host_alphabet);
-                }
-                // add new host to the register with the generated id
-                let now = SystemTime::now();
-                let new_host = proto::Host {
-                    id: id.clone(),
-                    addr: addr.clone(),
-                    last_sent_ping: now,
-                    last_received_ping: now,
-                    delete_later: false,
-                };
-                // add to registers.
-                host_register.insert(id.clone(), new_host);
-                host_map.insert(addr.clone(), id.clone());
-                println!("Added {:?} to the host register.", &addr);
-                // remove it from the activity map since its a known host. the map should only be for clients
-                enet_host.peer_activity_map.remove(&addr);
-            }
-            // send RegisterResponse
-            let response = proto::MsgType::HostRegisterResponse { host_code: id };
-            let data_to_send = serde_json::to_string(&response).unwrap_or_default();
-            if !data_to_send.is_empty() {
-                enet_host.send_to_target(data_to_send.as_bytes(), addr.clone())
-                    .unwrap();
+        if frame[0] == crypto::FRAME_KEY_ROTATE {
+            let Some(their_ephemeral_public) = crypto::decode_rotate_frame(frame) else {
+                continue;
+            };
+            if let Some(session) = peer_sessions.get_mut(&addr) {
+                let shared_secret = identity.diffie_hellman(&their_ephemeral_public);
+                session.rotate(shared_secret);
             }
             continue;
         }
-        // HostLookupRequest
-        if request["msg_type"] == "HostLookupRequest" {
-            let id_to_find = request["host_code"].as_str().unwrap_or_default();
-            let mut response = proto::MsgType::HostLookupResponse {
-                success: false,
-                host_info: String::new(),
+        let payload: Vec<u8> = if frame[0] == crypto::FRAME_ENCRYPTED {
+            let Some((counter, ciphertext)) = crypto::decode_data_frame(frame) else {
+                continue;
             };
-            if id_to_find.is_empty() {
-                // no hostcode send failed response.
-                let data_to_send = serde_json::to_string(&response).unwrap_or_default();
-                if !data_to_send.is_empty() {
-                    enet_host.send_to_target(data_to_send.as_bytes(), addr.clone())
-                        .unwrap();
-                }
+            let Some(session) = peer_sessions.get(&addr) else {
+                continue;
+            };
+            let Some(plaintext) = session.decrypt(counter, ciphertext) else {
                 continue;
+            };
+            plaintext
+        } else {
+            frame.to_vec()
+        };
+        let Some((request, format)) = decode_message(&payload) else {
+            continue;
+        };
+        peer_wire_formats.insert(addr.clone(), format);
+        match request {
+            proto::MsgType::PingRequest => {
+                // check if ping comes from a host
+                let Some(host_id) = host_map.get(&addr) else {
+                    continue;
+                };
+                if let Some(host_info) = host_register.get_mut(host_id) {
+                    host_info.last_received_ping = SystemTime::now();
+                    let response = proto::MsgType::PingResponse;
+                    send_message(&mut enet_host, &mut peer_sessions, &peer_wire_formats, &mut pending_handshakes, &identity, &addr, &response);
+                }
+            }
+            proto::MsgType::HostRegisterRequest { tag, player_count } => {
+                // absent fields mean "unchanged", not "cleared" - a host that
+                // re-registers just to refresh its ping shouldn't wipe out the
+                // tag/player_count it reported earlier.
+                let tag = proto::clamp_tag(tag);
+                let mut id = nanoid!(host_id_length, &host_alphabet);
+                // check if the host_map already has an id for this socket
+                if let Some(host_id) = host_map.get(&addr) {
+                    id = host_id.clone();
+                    if let Some(host) = host_register.get_mut(&id) {
+                        host.last_received_ping = SystemTime::now();
+                        if tag.is_some() {
+                            host.tag = tag;
+                        }
+                        if let Some(player_count) = player_count {
+                            host.player_count = player_count;
+                        }
+                    }
+                } else {
+                    while host_register.get(&id).is_some() {
+                        id = nanoid!(host_id_length, &host_alphabet);
+                    }
+                    // add new host to the register with the generated id
+                    let now = SystemTime::now();
+                    let new_host = proto::Host {
+                        id: id.clone(),
+                        addr: addr.clone(),
+                        last_sent_ping: now,
+                        last_received_ping: now,
+                        delete_later: false,
+                        tag,
+                        player_count: player_count.unwrap_or(0),
+                    };
+                    // add to registers.
+                    host_register.insert(id.clone(), new_host);
+                    host_map.insert(addr.clone(), id.clone());
+                    println!("Added {:?} to the host register.", &addr);
+                    // remove it from the activity map since its a known host. the map should only be for clients
+                    enet_host.peer_activity_map.remove(&addr);
+                }
+                // send RegisterResponse, including the address we observed the
+                // request come from so the host learns its own reflexive mapping.
+                let response = proto::MsgType::HostRegisterResponse {
+                    host_code: id,
+                    reflexive_addr: addr.clone(),
+                };
+                send_message(&mut enet_host, &mut peer_sessions, &peer_wire_formats, &mut pending_handshakes, &identity, &addr, &response);
             }
-            // if host_register has the wanted host. send a response with the host info.
-            if let Some(host_info) = host_register.get(id_to_find) {
-                response = proto::MsgType::HostLookupResponse {
-                    success: true,
-                    host_info: host_info.addr.to_string(),
+            // HostListRequest: server-browser paging over host_register,
+            // capped so a response can't overrun a single UDP datagram.
+            proto::MsgType::HostListRequest {
+                filter,
+                offset,
+                limit,
+            } => {
+                let filter = filter.map(|filter| filter.to_lowercase());
+                let offset = offset as usize;
+                let limit = limit.clamp(1, proto::MAX_HOST_LIST_LIMIT) as usize;
+                let mut matching: Vec<&proto::Host> = host_register
+                    .values()
+                    .filter(|host| match &filter {
+                        Some(filter) => host
+                            .tag
+                            .as_deref()
+                            .map(|tag| tag.to_lowercase().contains(filter.as_str()))
+                            .unwrap_or(false),
+                        None => true,
+                    })
+                    .collect();
+                matching.sort_by(|a, b| a.id.cmp(&b.id));
+                let total = matching.len() as u32;
+                // `limit` only bounds the entry *count*; a page of otherwise
+                // max-size entries could still outgrow the recv buffer, so
+                // paging also stops once the actual encoded response would -
+                // checked against whichever wire format `addr` is on (JSON
+                // and the compact binary codec serialize to different sizes
+                // for the same entries) and, if `addr` has an encrypted
+                // session, the extra bytes `send_message` will add to wrap
+                // it in a data frame.
+                let format = peer_wire_formats
+                    .get(&addr)
+                    .copied()
+                    .unwrap_or(wire::Format::Binary);
+                let overhead = if peer_sessions.contains_key(&addr) {
+                    crypto::ENCRYPTED_FRAME_OVERHEAD
+                } else {
+                    0
                 };
-                // and send ClientLookupResponse to the host
-                let host_response = proto::MsgType::ClientLookupResponse {
-                    client_info: addr.to_string(),
+                let mut entries = Vec::new();
+                for host in matching.into_iter().skip(offset).take(limit) {
+                    entries.push(proto::HostListEntry {
+                        host_code: host.id.clone(),
+                        addr: host.addr.clone(),
+                        tag: host.tag.clone(),
+                        player_count: host.player_count,
+                    });
+                    let candidate = proto::MsgType::HostListResponse {
+                        entries: entries.clone(),
+                        total,
+                    };
+                    if wire::encode(&candidate, format).len() + overhead > proto::RECV_BUFFER_SIZE {
+                        entries.pop();
+                        break;
+                    }
+                }
+                let response = proto::MsgType::HostListResponse { entries, total };
+                send_message(&mut enet_host, &mut peer_sessions, &peer_wire_formats, &mut pending_handshakes, &identity, &addr, &response);
+            }
+            proto::MsgType::HostLookupRequest { host_code } => {
+                if host_code.is_empty() {
+                    // no hostcode send failed response.
+                    let response = proto::MsgType::HostLookupResponse {
+                        success: false,
+                        host_info: String::new(),
+                        punch_at: 0,
+                    };
+                    send_message(&mut enet_host, &mut peer_sessions, &peer_wire_formats, &mut pending_handshakes, &identity, &addr, &response);
+                    continue;
+                }
+                // if host_register has the wanted host. send a response with the host info.
+                if let Some(host_info) = host_register.get(&host_code) {
+                    // tell both sides to attempt their simultaneous-open at the
+                    // same instant, a little in the future so this message has
+                    // time to reach both of them first.
+                    let punch_at = SystemTime::now() + proto::PUNCH_COORDINATION_DELAY;
+                    let punch_at_millis = unix_millis(punch_at);
+                    let response = proto::MsgType::HostLookupResponse {
+                        success: true,
+                        host_info: host_info.addr.to_string(),
+                        punch_at: punch_at_millis,
+                    };
+                    // and send ClientLookupResponse to the host
+                    let host_response = proto::MsgType::ClientLookupResponse {
+                        client_info: addr.to_string(),
+                        punch_at: punch_at_millis,
+                    };
+                    send_message(
+                        &mut enet_host,
+                        &mut peer_sessions,
+                        &peer_wire_formats,
+                        &mut pending_handshakes,
+                        &identity,
+                        &host_info.addr.clone(),
+                        &host_response,
+                    );
+                    send_message(&mut enet_host, &mut peer_sessions, &peer_wire_formats, &mut pending_handshakes, &identity, &addr, &response);
+                    // a retried lookup for the same pairing replaces its old
+                    // window rather than piling up a duplicate one.
+                    let host_addr = host_info.addr.clone();
+                    pending_punches.retain(|punch| {
+                        punch.host_addr != host_addr || punch.client_addr != addr
+                    });
+                    pending_punches.push(proto::PendingPunch {
+                        host_addr,
+                        client_addr: addr.clone(),
+                        deadline: punch_at + proto::PUNCH_TIMEOUT,
+                    });
+                    continue;
+                }
+                // not registered locally: fall back to an iterative lookup across
+                // the sibling registrars this node knows about.
+                let lookup_id = nanoid!();
+                let mut lookup =
+                    dht::PendingLookup::new(host_code, addr.clone(), &routing_table);
+                if lookup.closest.is_empty() {
+                    let response = proto::MsgType::HostLookupResponse {
+                        success: false,
+                        host_info: String::new(),
+                        punch_at: 0,
+                    };
+                    send_message(&mut enet_host, &mut peer_sessions, &peer_wire_formats, &mut pending_handshakes, &identity, &addr, &response);
+                    continue;
+                }
+                advance_lookup(
+                    &mut enet_host,
+                    &mut peer_sessions,
+                    &peer_wire_formats,
+                    &mut pending_handshakes,
+                    &identity,
+                    &self_node_id_b62,
+                    &lookup_id,
+                    &mut lookup,
+                );
+                pending_lookups.insert(lookup_id, lookup);
+            }
+            // PunchSuccessNotice: either side of a pairing confirming its punch
+            // worked, so the timeout sweep above doesn't later flag it as
+            // needing a relay.
+            proto::MsgType::PunchSuccessNotice => {
+                pending_punches
+                    .retain(|punch| punch.host_addr != addr && punch.client_addr != addr);
+            }
+            // DhtLookupRequest: a sibling registrar wants to know if we hold
+            // `host_code`, or failing that, who we know that's closer to it.
+            proto::MsgType::DhtLookupRequest {
+                lookup_id,
+                host_code,
+                from_node_id,
+            } => {
+                if let Some(from_node_id) = dht::NodeId::from_base62(&from_node_id) {
+                    routing_table.insert(from_node_id, addr.clone());
+                }
+                let response = if let Some(host_info) = host_register.get(&host_code) {
+                    proto::MsgType::DhtLookupResponse {
+                        lookup_id,
+                        found: true,
+                        host_info: host_info.addr.to_string(),
+                        closer_nodes: Vec::new(),
+                        from_node_id: self_node_id_b62.clone(),
+                    }
+                } else {
+                    let target = dht::NodeId::from_host_code(&host_code);
+                    let closer_nodes = routing_table
+                        .closest(&target, dht::K_BUCKET_SIZE)
+                        .into_iter()
+                        .map(|peer| (peer.node_id.to_base62(), peer.addr))
+                        .collect();
+                    proto::MsgType::DhtLookupResponse {
+                        lookup_id,
+                        found: false,
+                        host_info: String::new(),
+                        closer_nodes,
+                        from_node_id: self_node_id_b62.clone(),
+                    }
+                };
+                send_message(&mut enet_host, &mut peer_sessions, &peer_wire_formats, &mut pending_handshakes, &identity, &addr, &response);
+            }
+            // DhtLookupResponse: a step of a lookup we originated has come back.
+            proto::MsgType::DhtLookupResponse {
+                lookup_id,
+                found,
+                host_info,
+                closer_nodes,
+                from_node_id,
+            } => {
+                if let Some(from_node_id) = dht::NodeId::from_base62(&from_node_id) {
+                    routing_table.insert(from_node_id, addr.clone());
+                }
+                let Some(lookup) = pending_lookups.get_mut(&lookup_id) else {
+                    continue;
                 };
-                let data_for_host = serde_json::to_string(&host_response).unwrap_or_default();
-                if !data_for_host.is_empty() {
-                    enet_host.send_to_target(data_for_host.as_bytes(), host_info.addr.clone())
-                        .unwrap();
+                if let Some(responder_id) = dht::NodeId::from_base62(&from_node_id) {
+                    lookup.awaiting.retain(|id| *id != responder_id);
+                }
+                if found {
+                    let lookup = pending_lookups.remove(&lookup_id).unwrap();
+                    // no live punch coordination across registrars in this
+                    // version: the remote registrar holding the host isn't sent
+                    // a ClientLookupResponse, so there's nothing to synchronize.
+                    let response = proto::MsgType::HostLookupResponse {
+                        success: true,
+                        host_info,
+                        punch_at: 0,
+                    };
+                    send_message(
+                        &mut enet_host,
+                        &mut peer_sessions,
+                        &peer_wire_formats,
+                        &mut pending_handshakes,
+                        &identity,
+                        &lookup.originator_addr,
+                        &response,
+                    );
+                    continue;
+                }
+                // `peer_addr` comes straight from another registrar's gossip,
+                // so it's validated as a real address `enet_host` can send
+                // to before it ever reaches the routing table - an
+                // unparseable, IPv6, or malicious entry is dropped now
+                // rather than wasting a query attempt only to have
+                // `send_to_target` reject it later.
+                let closer_nodes: Vec<dht::RegistrarPeer> = closer_nodes
+                    .into_iter()
+                    .filter_map(|(node_id, peer_addr)| {
+                        if !proto::is_enet_target(&peer_addr) {
+                            return None;
+                        }
+                        Some(dht::RegistrarPeer {
+                            node_id: dht::NodeId::from_base62(&node_id)?,
+                            addr: peer_addr,
+                            last_seen: SystemTime::now(),
+                        })
+                    })
+                    .collect();
+                lookup.merge_closer_nodes(closer_nodes);
+                if lookup.is_exhausted() {
+                    let lookup = pending_lookups.remove(&lookup_id).unwrap();
+                    let response = proto::MsgType::HostLookupResponse {
+                        success: false,
+                        host_info: String::new(),
+                        punch_at: 0,
+                    };
+                    send_message(
+                        &mut enet_host,
+                        &mut peer_sessions,
+                        &peer_wire_formats,
+                        &mut pending_handshakes,
+                        &identity,
+                        &lookup.originator_addr,
+                        &response,
+                    );
+                } else if lookup.awaiting.is_empty() {
+                    advance_lookup(
+                        &mut enet_host,
+                        &mut peer_sessions,
+                        &peer_wire_formats,
+                        &mut pending_handshakes,
+                        &identity,
+                        &self_node_id_b62,
+                        &lookup_id,
+                        lookup,
+                    );
                 }
             }
-            let data_to_send = serde_json::to_string(&response).unwrap_or_default();
-            if !data_to_send.is_empty() {
-                enet_host.send_to_target(data_to_send.as_bytes(), addr.clone())
-                    .unwrap();
+            proto::MsgType::PingResponse
+            | proto::MsgType::HostRegisterResponse { .. }
+            | proto::MsgType::HostLookupResponse { .. }
+            | proto::MsgType::ClientLookupResponse { .. }
+            | proto::MsgType::PunchTimeoutNotice { .. }
+            | proto::MsgType::HostListResponse { .. } => {
+                // registrar never receives these - they're only ever sent, to
+                // a host or client - so there's nothing to dispatch on.
             }
-            continue;
         }
     }
 }
@@ -1,17 +1,60 @@
 use enet::*;
 use serde::{Deserialize, Serialize};
 use std::hash::{Hash, Hasher};
-use std::net::Ipv4Addr;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    net::Ipv4Addr,
     net::SocketAddr,
-    net::SocketAddrV4,
     time::{Duration, SystemTime},
 };
 use tokio::net::UdpSocket;
 
 const PING_INTERVAL: Duration = Duration::from_secs(10);
-const PEER_REMOVAL_TIMEMOUT: Duration = Duration::from_secs(30);
+// also reused by `main` to expire stale dht routing-table entries and time
+// out in-flight federated lookups, so it's shared rather than private.
+pub(crate) const PEER_REMOVAL_TIMEMOUT: Duration = Duration::from_secs(30);
+// gap between a punch notice being sent and the moment it tells both sides
+// to punch at, so the message has time to actually arrive first.
+pub const PUNCH_COORDINATION_DELAY: Duration = Duration::from_millis(250);
+// how long after `punch_at` the registrar waits before giving up on a
+// pairing and flagging it as needing a relay instead.
+pub const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+// caps the number of entries in a HostListResponse page, regardless of what
+// a client asks for in `limit`. This alone doesn't bound the page's byte
+// size; `main` also stops a page before it outgrows `RECV_BUFFER_SIZE`.
+pub const MAX_HOST_LIST_LIMIT: u32 = 32;
+
+fn default_host_list_limit() -> u32 {
+    MAX_HOST_LIST_LIMIT
+}
+
+// a host's self-reported server-browser tag is free-form display metadata,
+// not something worth bouncing a registration over, so it's truncated
+// rather than rejected - but still capped, since `MAX_HOST_LIST_LIMIT`
+// alone only bounds the entry *count*, and an unbounded tag on each of
+// those entries can still blow the page past a single UDP datagram.
+pub const MAX_TAG_LENGTH: usize = 64;
+
+// the receive buffer `poll_messages` decodes into (see `main`'s fixed-size
+// `buf`). A HostListResponse page is kept under this so the reply back
+// doesn't get silently dropped the same way an oversized request would be.
+pub const RECV_BUFFER_SIZE: usize = 1024;
+
+// truncates `tag` to `MAX_TAG_LENGTH` bytes, on a char boundary so it's
+// still valid UTF-8.
+pub fn clamp_tag(tag: Option<String>) -> Option<String> {
+    tag.map(|mut tag| {
+        if tag.len() > MAX_TAG_LENGTH {
+            let mut cut = MAX_TAG_LENGTH;
+            while !tag.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            tag.truncate(cut);
+        }
+        tag
+    })
+}
+
 
 // Message Types
 #[derive(Serialize, Deserialize)]
@@ -19,11 +62,86 @@ const PEER_REMOVAL_TIMEMOUT: Duration = Duration::from_secs(30);
 pub enum MsgType {
     PingRequest,
     PingResponse,
-    HostRegisterRequest,
-    HostRegisterResponse { host_code: String },
-    HostLookupRequest { host_code: String },
-    HostLookupResponse { success: bool, host_info: String },
-    ClientLookupResponse { client_info: String },
+    // `tag`/`player_count` are optional server-browser metadata the host
+    // supplies about itself; `#[serde(default)]` keeps this backward
+    // compatible with hosts that only ever sent the bare message.
+    HostRegisterRequest {
+        #[serde(default)]
+        tag: Option<String>,
+        #[serde(default)]
+        player_count: Option<u32>,
+    },
+    // `reflexive_addr` is the server-observed address the request arrived
+    // from, so the host learns its own external (post-NAT) address mapping.
+    HostRegisterResponse {
+        host_code: String,
+        reflexive_addr: String,
+    },
+    HostLookupRequest {
+        #[serde(default)]
+        host_code: String,
+    },
+    // `punch_at` (unix millis) is a synchronized time both sides are told to
+    // attempt their simultaneous-open at, giving symmetric-NAT hole-punching
+    // a fighting chance; 0 when `success` is false or the host was found via
+    // federation, where no live coordination with the remote host happens.
+    HostLookupResponse {
+        success: bool,
+        host_info: String,
+        punch_at: u64,
+    },
+    ClientLookupResponse {
+        client_info: String,
+        punch_at: u64,
+    },
+    // sent to both sides of a pairing if the punch coordination window
+    // (`PUNCH_TIMEOUT` past `punch_at`) passes with no sign of success, so
+    // they know to fall back to a relay instead of continuing to punch.
+    PunchTimeoutNotice { relay_needed: bool },
+    // either side of a pairing sends this once its punch succeeds, so the
+    // registrar can cancel the pending window instead of later sending a
+    // stale `PunchTimeoutNotice` for a connection that's already up.
+    PunchSuccessNotice,
+    // server-browser query: list registered hosts a page at a time,
+    // optionally filtered by tag, so a client can browse without already
+    // knowing a host_code.
+    HostListRequest {
+        #[serde(default)]
+        filter: Option<String>,
+        #[serde(default)]
+        offset: u32,
+        #[serde(default = "default_host_list_limit")]
+        limit: u32,
+    },
+    HostListResponse {
+        entries: Vec<HostListEntry>,
+        total: u32,
+    },
+    // registrar-to-registrar federation (see the `dht` module): combined
+    // FIND_NODE/FIND_VALUE step of an iterative Kademlia-style lookup for a
+    // host_code this registrar doesn't have in its own `host_register`.
+    // `from_node_id` lets the receiver learn the sender's routing-table entry.
+    DhtLookupRequest {
+        lookup_id: String,
+        host_code: String,
+        from_node_id: String,
+    },
+    DhtLookupResponse {
+        lookup_id: String,
+        found: bool,
+        host_info: String,
+        closer_nodes: Vec<(String, String)>,
+        from_node_id: String,
+    },
+}
+
+// one page entry in a HostListResponse.
+#[derive(Serialize, Deserialize)]
+pub struct HostListEntry {
+    pub host_code: String,
+    pub addr: String,
+    pub tag: Option<String>,
+    pub player_count: u32,
 }
 
 // Host Information
@@ -33,6 +151,23 @@ pub struct Host {
     pub last_sent_ping: SystemTime,
     pub last_received_ping: SystemTime,
     pub delete_later: bool,
+    pub tag: Option<String>,
+    pub player_count: u32,
+}
+
+// tracks one in-flight hole-punch coordination between a host and a client
+// that a lookup just matched, so the registrar can notice if the window
+// passed with no sign the punch worked and flag that a relay is needed.
+pub struct PendingPunch {
+    pub host_addr: String,
+    pub client_addr: String,
+    pub deadline: SystemTime,
+}
+
+impl PendingPunch {
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.deadline
+    }
 }
 
 impl Host {
@@ -56,10 +191,19 @@ pub trait SocketAgnosticInterface {
     fn poll_messages(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, String)>;
 }
 
+fn invalid_target_addr(target: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("invalid target address: {target}"),
+    )
+}
+
 // example SAI for tokio::net::UdpSocket
 impl SocketAgnosticInterface for UdpSocket {
     fn send_to_target(&mut self, bytes: &[u8], target: String) -> std::io::Result<usize> {
-        let addr = target.parse::<SocketAddr>().unwrap();
+        let addr = target
+            .parse::<SocketAddr>()
+            .map_err(|_| invalid_target_addr(&target))?;
         let result = self.try_send_to(&bytes, addr);
         if result.is_err() {
             Err(result.unwrap_err())
@@ -100,9 +244,74 @@ impl EnetHost {
             }
         }
     }
-    pub fn init(port: u16, max_concurrent_peers: usize) -> Self {
+    // addresses of every currently-connected ENet peer, in the same string
+    // form every other per-peer map in this file is keyed by. `poll_messages`
+    // doesn't surface Connect/Disconnect events to its caller, so this is
+    // what lets `main` prune encrypted-transport state for peers that have
+    // dropped. `sock.peers()` yields one entry per allocated peer slot
+    // regardless of state - a disconnected peer's slot keeps its last address
+    // until ENet reuses it for a new connection - so this has to filter down
+    // to `PeerState::Connected`, not just collect every address it sees.
+    pub fn connected_addrs(&mut self) -> HashSet<String> {
+        self.sock
+            .peers()
+            .filter(|peer| peer.state() == enet::PeerState::Connected)
+            .map(|peer| {
+                EnetAddr {
+                    addr: peer.address(),
+                }
+                .to_string()
+            })
+            .collect()
+    }
+
+    // addresses of every ENet peer slot that isn't fully disconnected -
+    // unlike `connected_addrs`, this includes a peer mid-connect. A target
+    // `send_message` just dialed out to (via `send_to_target`'s on-demand
+    // `connect`) sits in `PeerState::Connecting`, not `Connected`, for at
+    // least a round trip, so pruning its still-pending handshake bookkeeping
+    // against `connected_addrs` would drop and immediately resend it on
+    // every tick until the connection completes.
+    pub fn known_peer_addrs(&mut self) -> HashSet<String> {
+        self.sock
+            .peers()
+            .filter(|peer| peer.state() != enet::PeerState::Disconnected)
+            .map(|peer| {
+                EnetAddr {
+                    addr: peer.address(),
+                }
+                .to_string()
+            })
+            .collect()
+    }
+
+    // disconnects a peer by its string address and drops it from
+    // `peer_activity_map`, e.g. when it fails an encrypted-transport handshake.
+    pub fn disconnect_addr(&mut self, addr: &str) {
+        for peer in self.sock.peers() {
+            let peer_addr = EnetAddr {
+                addr: peer.address(),
+            };
+            if peer_addr.to_string() == addr {
+                peer.disconnect_now(0);
+                self.peer_activity_map.remove(&peer_addr);
+                break;
+            }
+        }
+    }
+
+    // takes an `Ipv4Addr` rather than a hardcoded loopback, but stops short of
+    // the `IpAddr`/`SocketAddr` (including IPv6) this was originally asked to
+    // accept - the `enet` crate's `Address`, and so the underlying ENet
+    // protocol this host speaks, is hard-coded to IPv4, so there's no V6
+    // listener to bind here even in principle. `EnetAddr` below is narrowed
+    // the same way, for the same reason. Hosts that need a real dual-stack
+    // listener should use the `UdpSocket` `SocketAgnosticInterface` impl
+    // above instead, which sends to any `SocketAddr`, IPv4 or IPv6, on a
+    // socket the caller has already bound.
+    pub fn init(bind_addr: Ipv4Addr, port: u16, max_concurrent_peers: usize) -> Self {
         let enet = Enet::new().expect("failed to init enet");
-        let local_addr = enet::Address::new(Ipv4Addr::LOCALHOST, port);
+        let local_addr = enet::Address::new(bind_addr, port);
         let host = enet
             .create_host(
                 Some(&local_addr),
@@ -120,6 +329,10 @@ impl EnetHost {
     }
 }
 
+// wraps `enet::Address` (IPv4-only, see `EnetHost::init`) rather than a
+// general `SocketAddr`, so `Hash`/`Eq`/`ToString` here don't round-trip IPv6
+// either - there's nothing for them to round-trip, since this host can never
+// hold an IPv6 peer in the first place.
 pub struct EnetAddr {
     pub addr: enet::Address,
 }
@@ -153,9 +366,32 @@ impl ToString for EnetAddr {
     }
 }
 
+// whether `addr` is a target `EnetHost::send_to_target` can actually reach -
+// enet only speaks IPv4, so anything else (unparseable, or a V6 address) is
+// rejected. Shared with callers that gossip addresses around (`main`'s dht
+// message handling) so a bad entry is dropped before it's stored, not just
+// when it's finally sent to.
+pub fn is_enet_target(addr: &str) -> bool {
+    matches!(addr.parse::<SocketAddr>(), Ok(SocketAddr::V4(_)))
+}
+
 impl SocketAgnosticInterface for EnetHost {
     fn send_to_target(&mut self, buf: &[u8], target: String) -> std::io::Result<usize> {
-        let target_addr = Address::from(target.parse::<SocketAddrV4>().unwrap());
+        // parse as a general `SocketAddr` (rather than requiring the caller
+        // already know enet is IPv4-only), but still reject anything that
+        // isn't a V4 address - and reject it as an error, not a panic, since
+        // `target` can come from another registrar's gossip and shouldn't be
+        // able to take this one down with a malformed or IPv6 string.
+        let target_addr = match target.parse::<SocketAddr>() {
+            Ok(SocketAddr::V4(v4)) => Address::from(v4),
+            Ok(SocketAddr::V6(_)) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("enet only speaks IPv4, got: {target}"),
+                ));
+            }
+            Err(_) => return Err(invalid_target_addr(&target)),
+        };
         for mut peer in self.sock.peers() {
             if peer.address() == target_addr {
                 let msg = Packet::new(buf, PacketMode::ReliableSequenced).unwrap();
@@ -168,7 +404,20 @@ impl SocketAgnosticInterface for EnetHost {
             }
             continue;
         }
-        Ok(buf.len())
+        // no peer for this address yet - this host has never exchanged
+        // packets with it before (e.g. a sibling registrar from
+        // `known_registrars.txt` that hasn't dialed in), so dial out rather
+        // than silently dropping the message. ENet queues packets handed to
+        // a still-connecting peer and delivers them once the handshake
+        // completes, so the send below isn't lost even though
+        // `Event::Connect` for it hasn't fired yet.
+        let mut peer = self
+            .sock
+            .connect(&target_addr, 1, 0)
+            .map_err(|_| invalid_target_addr(&target))?;
+        let msg = Packet::new(buf, PacketMode::ReliableSequenced).unwrap();
+        let result = peer.send_packet(msg, 0);
+        Ok(if result.is_ok() { buf.len() } else { 0 })
     }
 
     fn poll_messages(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, String)> {
@@ -194,6 +443,14 @@ impl SocketAgnosticInterface for EnetHost {
                     packet,
                 } => {
                     let data = packet.data();
+                    if data.len() > buf.len() {
+                        println!(
+                            "dropped oversized packet from: {:?}, len: {}",
+                            sender.address(),
+                            data.len(),
+                        );
+                        return Ok((0, String::new()));
+                    }
                     buf[..data.len()].copy_from_slice(data);
                     println!(
                         "Received packet from: {:?}, len: {}, channel: {}",
@@ -0,0 +1,315 @@
+// Compact binary codec for `MsgType`, offered as a faster alternative to the
+// JSON encoding: a one-byte discriminant followed by length-prefixed fields,
+// rather than a full `serde_json` parse per packet. `BINARY_MAGIC` is the
+// version/format byte every encoded frame starts with - distinct from the
+// encrypted-transport's reserved frame bytes (`crypto::FRAME_*`, 0x01-0x03)
+// and from plaintext JSON's leading `{` (0x7B) - so `poll_messages`
+// consumers can tell the two formats apart before picking a decoder.
+use crate::proto::{HostListEntry, MsgType};
+
+pub const BINARY_MAGIC: u8 = 0x10;
+
+// which wire format a message was (or should be) encoded in. A peer is
+// tracked under whichever of these it was last seen sending, so a reply
+// matches the request rather than assuming every peer already speaks the
+// compact codec.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Binary,
+    Json,
+}
+
+// encodes `msg` as `format` asks: the compact binary codec, or plain JSON for
+// a peer still on the previous protocol version. JSON encoding can't fail in
+// practice (`MsgType` has no types `serde_json` can't represent), so an
+// encode failure here falls back to an empty frame the same way it did
+// before this codec existed.
+pub fn encode(msg: &MsgType, format: Format) -> Vec<u8> {
+    match format {
+        Format::Binary => encode_binary(msg),
+        Format::Json => serde_json::to_string(msg).unwrap_or_default().into_bytes(),
+    }
+}
+
+fn write_u8(out: &mut Vec<u8>, v: u8) {
+    out.push(v);
+}
+
+fn write_bool(out: &mut Vec<u8>, v: bool) {
+    out.push(v as u8);
+}
+
+fn write_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn write_u64(out: &mut Vec<u8>, v: u64) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_opt_str(out: &mut Vec<u8>, s: &Option<String>) {
+    match s {
+        Some(s) => {
+            write_bool(out, true);
+            write_str(out, s);
+        }
+        None => write_bool(out, false),
+    }
+}
+
+fn write_opt_u32(out: &mut Vec<u8>, v: &Option<u32>) {
+    match v {
+        Some(v) => {
+            write_bool(out, true);
+            write_u32(out, *v);
+        }
+        None => write_bool(out, false),
+    }
+}
+
+// little cursor over the frame body (past the magic byte), so decoding reads
+// top to bottom the same order `encode` wrote in, with `?` doing the bounds
+// checking instead of a panic on a short or malformed frame.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        let byte = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        Some(self.u8()? != 0)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(self.pos..self.pos + 4)?.try_into().ok()?;
+        self.pos += 4;
+        Some(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let bytes: [u8; 8] = self.data.get(self.pos..self.pos + 8)?.try_into().ok()?;
+        self.pos += 8;
+        Some(u64::from_be_bytes(bytes))
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+
+    fn opt_str(&mut self) -> Option<Option<String>> {
+        if self.bool()? {
+            Some(Some(self.str()?))
+        } else {
+            Some(None)
+        }
+    }
+
+    fn opt_u32(&mut self) -> Option<Option<u32>> {
+        if self.bool()? {
+            Some(Some(self.u32()?))
+        } else {
+            Some(None)
+        }
+    }
+}
+
+// encodes `msg` as `BINARY_MAGIC` followed by a one-byte discriminant and its
+// length-prefixed fields, in the same order the enum declares them.
+fn encode_binary(msg: &MsgType) -> Vec<u8> {
+    let mut out = vec![BINARY_MAGIC];
+    match msg {
+        MsgType::PingRequest => write_u8(&mut out, 0),
+        MsgType::PingResponse => write_u8(&mut out, 1),
+        MsgType::HostRegisterRequest { tag, player_count } => {
+            write_u8(&mut out, 2);
+            write_opt_str(&mut out, tag);
+            write_opt_u32(&mut out, player_count);
+        }
+        MsgType::HostRegisterResponse {
+            host_code,
+            reflexive_addr,
+        } => {
+            write_u8(&mut out, 3);
+            write_str(&mut out, host_code);
+            write_str(&mut out, reflexive_addr);
+        }
+        MsgType::HostLookupRequest { host_code } => {
+            write_u8(&mut out, 4);
+            write_str(&mut out, host_code);
+        }
+        MsgType::HostLookupResponse {
+            success,
+            host_info,
+            punch_at,
+        } => {
+            write_u8(&mut out, 5);
+            write_bool(&mut out, *success);
+            write_str(&mut out, host_info);
+            write_u64(&mut out, *punch_at);
+        }
+        MsgType::ClientLookupResponse {
+            client_info,
+            punch_at,
+        } => {
+            write_u8(&mut out, 6);
+            write_str(&mut out, client_info);
+            write_u64(&mut out, *punch_at);
+        }
+        MsgType::PunchTimeoutNotice { relay_needed } => {
+            write_u8(&mut out, 7);
+            write_bool(&mut out, *relay_needed);
+        }
+        MsgType::PunchSuccessNotice => write_u8(&mut out, 8),
+        MsgType::HostListRequest {
+            filter,
+            offset,
+            limit,
+        } => {
+            write_u8(&mut out, 9);
+            write_opt_str(&mut out, filter);
+            write_u32(&mut out, *offset);
+            write_u32(&mut out, *limit);
+        }
+        MsgType::HostListResponse { entries, total } => {
+            write_u8(&mut out, 10);
+            write_u32(&mut out, entries.len() as u32);
+            for entry in entries {
+                write_str(&mut out, &entry.host_code);
+                write_str(&mut out, &entry.addr);
+                write_opt_str(&mut out, &entry.tag);
+                write_u32(&mut out, entry.player_count);
+            }
+            write_u32(&mut out, *total);
+        }
+        MsgType::DhtLookupRequest {
+            lookup_id,
+            host_code,
+            from_node_id,
+        } => {
+            write_u8(&mut out, 11);
+            write_str(&mut out, lookup_id);
+            write_str(&mut out, host_code);
+            write_str(&mut out, from_node_id);
+        }
+        MsgType::DhtLookupResponse {
+            lookup_id,
+            found,
+            host_info,
+            closer_nodes,
+            from_node_id,
+        } => {
+            write_u8(&mut out, 12);
+            write_str(&mut out, lookup_id);
+            write_bool(&mut out, *found);
+            write_str(&mut out, host_info);
+            write_u32(&mut out, closer_nodes.len() as u32);
+            for (node_id, addr) in closer_nodes {
+                write_str(&mut out, node_id);
+                write_str(&mut out, addr);
+            }
+            write_str(&mut out, from_node_id);
+        }
+    }
+    out
+}
+
+// decodes a frame previously produced by `encode`. Returns `None` on a short
+// read, a bad discriminant, or non-UTF8 string data, rather than panicking -
+// callers fall back to JSON (or drop the packet) the same way a JSON parse
+// failure is already handled.
+pub fn decode(frame: &[u8]) -> Option<MsgType> {
+    if frame.first() != Some(&BINARY_MAGIC) {
+        return None;
+    }
+    let mut reader = Reader::new(&frame[1..]);
+    match reader.u8()? {
+        0 => Some(MsgType::PingRequest),
+        1 => Some(MsgType::PingResponse),
+        2 => Some(MsgType::HostRegisterRequest {
+            tag: reader.opt_str()?,
+            player_count: reader.opt_u32()?,
+        }),
+        3 => Some(MsgType::HostRegisterResponse {
+            host_code: reader.str()?,
+            reflexive_addr: reader.str()?,
+        }),
+        4 => Some(MsgType::HostLookupRequest {
+            host_code: reader.str()?,
+        }),
+        5 => Some(MsgType::HostLookupResponse {
+            success: reader.bool()?,
+            host_info: reader.str()?,
+            punch_at: reader.u64()?,
+        }),
+        6 => Some(MsgType::ClientLookupResponse {
+            client_info: reader.str()?,
+            punch_at: reader.u64()?,
+        }),
+        7 => Some(MsgType::PunchTimeoutNotice {
+            relay_needed: reader.bool()?,
+        }),
+        8 => Some(MsgType::PunchSuccessNotice),
+        9 => Some(MsgType::HostListRequest {
+            filter: reader.opt_str()?,
+            offset: reader.u32()?,
+            limit: reader.u32()?,
+        }),
+        10 => {
+            let count = reader.u32()? as usize;
+            let mut entries = Vec::with_capacity(count.min(4096));
+            for _ in 0..count {
+                entries.push(HostListEntry {
+                    host_code: reader.str()?,
+                    addr: reader.str()?,
+                    tag: reader.opt_str()?,
+                    player_count: reader.u32()?,
+                });
+            }
+            Some(MsgType::HostListResponse {
+                entries,
+                total: reader.u32()?,
+            })
+        }
+        11 => Some(MsgType::DhtLookupRequest {
+            lookup_id: reader.str()?,
+            host_code: reader.str()?,
+            from_node_id: reader.str()?,
+        }),
+        12 => {
+            let lookup_id = reader.str()?;
+            let found = reader.bool()?;
+            let host_info = reader.str()?;
+            let count = reader.u32()? as usize;
+            let mut closer_nodes = Vec::with_capacity(count.min(4096));
+            for _ in 0..count {
+                closer_nodes.push((reader.str()?, reader.str()?));
+            }
+            Some(MsgType::DhtLookupResponse {
+                lookup_id,
+                found,
+                host_info,
+                closer_nodes,
+                from_node_id: reader.str()?,
+            })
+        }
+        _ => None,
+    }
+}